@@ -6,8 +6,9 @@ use frame_support::{
     decl_error, decl_event, decl_module, decl_storage,
     dispatch::{DispatchError, DispatchResult},
     ensure,
-    traits::Randomness,
-    Parameter, RuntimeDebug, StorageDoubleMap, StorageValue,
+    traits::{Currency, ExistenceRequirement, Randomness},
+    weights::Weight,
+    Parameter, RuntimeDebug, StorageDoubleMap, StorageMap, StorageValue,
 };
 use frame_system::ensure_signed;
 use sp_io::hashing::blake2_128;
@@ -19,8 +20,17 @@ use sp_std::ops::Deref;
 #[cfg(test)]
 mod tests;
 
-#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
-pub struct Kitty(pub [u8; 16]);
+/// A kitty and its lineage. `dna` is its unique identifier and genetic
+/// material, `gen` is how many generations removed it is from a `create`d
+/// kitty, and `parents` records the two kitties it was bred from, if any.
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub struct Kitty<KittyId> {
+    pub dna: [u8; 16],
+    pub gen: u32,
+    pub parents: Option<(KittyId, KittyId)>,
+}
+
+pub type KittyOf<T> = Kitty<<T as Config>::KittyId>;
 
 #[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
 pub enum KittyGender {
@@ -28,9 +38,9 @@ pub enum KittyGender {
     Female,
 }
 
-impl Kitty {
+impl<KittyId> Kitty<KittyId> {
     pub fn gender(&self) -> KittyGender {
-        if self.0[0] % 2 == 0 {
+        if self.dna[0] % 2 == 0 {
             KittyGender::Male
         } else {
             KittyGender::Female
@@ -38,30 +48,97 @@ impl Kitty {
     }
 }
 
+/// A kitty lifecycle event, emitted to `Config::MessageSink` alongside the
+/// usual `deposit_event` so another runtime subsystem (an off-chain worker,
+/// a confidential contract, a sibling chain) can consume it without
+/// depending on event delivery.
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub enum KittyMessage<AccountId, KittyId> {
+    Created(AccountId, KittyId, [u8; 16]),
+    Transferred {
+        from: AccountId,
+        to: AccountId,
+        kitty_id: KittyId,
+    },
+}
+
+/// A typed egress point for `KittyMessage`s. Implement this to forward kitty
+/// lifecycle changes to another component; the default `()` implementation
+/// drops them.
+pub trait HandleKittyMessage<AccountId, KittyId> {
+    fn send(message: KittyMessage<AccountId, KittyId>);
+}
+
+impl<AccountId, KittyId> HandleKittyMessage<AccountId, KittyId> for () {
+    fn send(_message: KittyMessage<AccountId, KittyId>) {}
+}
+
 pub trait Config: frame_system::Config {
     type Event: From<Event<Self>> + Into<<Self as frame_system::Config>::Event>;
     type Randomness: Randomness<Self::Hash>;
     type KittyId: Parameter + AtLeast32BitUnsigned + Bounded + Default + Copy + Deref;
+    type Currency: Currency<Self::AccountId>;
+    type MessageSink: HandleKittyMessage<Self::AccountId, Self::KittyId>;
 }
 
+pub type BalanceOf<T> =
+    <<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
+
 decl_storage! {
     trait Store for Module<T: Config> as Kitties {
         /// Stores all the kitties, key is the kitty id
-        pub Kitties get(fn kitties): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) T::KittyId => Option<Kitty>;
+        pub Kitties get(fn kitties): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) T::KittyId => Option<KittyOf<T>>;
         /// Stores the next kitty ID
         pub NextKittyId get(fn next_kitty_id): T::KittyId;
+        /// Stores the number of kitties owned by each account, used to derive the
+        /// next free slot in `OwnedKittiesIndex`
+        pub OwnedKittiesCount get(fn owned_kitties_count): map hasher(blake2_128_concat) T::AccountId => u32;
+        /// Maps an owner and a dense index to one of their kitty ids, enabling
+        /// swap-and-pop removal without scanning `Kitties`
+        pub OwnedKittiesIndex get(fn owned_kitties_index): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) u32 => T::KittyId;
+        /// The reverse of `OwnedKittiesIndex`, used to find a kitty's slot in
+        /// O(1) when it is removed from an owner
+        pub KittyIndex get(fn kitty_index): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) T::KittyId => u32;
+        /// The price an owner is asking for one of their kitties. `None` means
+        /// the kitty is not for sale.
+        pub KittyPrices get(fn kitty_price): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) T::KittyId => Option<BalanceOf<T>>;
+        /// A monotonically increasing counter folded into every randomness
+        /// payload so that two mints in the same block never draw identical
+        /// randomness.
+        pub Nonce get(fn nonce): u64;
+        /// Tracks every DNA value that has ever been minted, so a freshly
+        /// generated DNA can be checked for global uniqueness.
+        pub KittyDnaExists get(fn kitty_dna_exists): map hasher(identity) [u8; 16] => bool;
+        /// The total number of kitties that have ever been minted
+        pub AllKittiesCount get(fn all_kitties_count): u64;
+        /// A dense, zero-based array of every kitty id, for off-chain clients
+        /// to page through the full collection
+        pub AllKittiesArray get(fn kitty_by_index): map hasher(twox_64_concat) u64 => T::KittyId;
+        /// The reverse of `AllKittiesArray`
+        pub AllKittiesIndex get(fn all_kitties_index): map hasher(blake2_128_concat) T::KittyId => u64;
+        /// The current owner of a kitty, keyed by the global kitty id so a
+        /// kitty can be looked up without already knowing who holds it
+        pub KittyOwner get(fn kitty_owner): map hasher(blake2_128_concat) T::KittyId => T::AccountId;
     }
 }
 
 decl_event! {
     pub enum Event<T>
         where <T as frame_system::Config>::AccountId,
-       <T as Config>::KittyId
+       <T as Config>::KittyId,
+       Balance = BalanceOf<T>,
+       Kitty = KittyOf<T>
     {
         /// A kitty is created. \[owner, kitty_id, kitty\]
         KittyCreated(AccountId, KittyId, Kitty),
-        /// A new kitten is bred. \[owner, kitty_id, kitty\]
-        KittyBred(AccountId, KittyId, Kitty),
+        /// A new kitten is bred. \[owner, kitty_id, kitty, generation\]
+        KittyBred(AccountId, KittyId, Kitty, u32),
+        /// A kitty is transferred. \[from, to, kitty_id\]
+        KittyTransferred(AccountId, AccountId, KittyId),
+        /// A kitty is sold. \[buyer, seller, kitty_id, price\]
+        KittySold(AccountId, AccountId, KittyId, Balance),
+        /// The total number of kitties ever minted has changed. \[total\]
+        TotalKittiesChanged(u64),
     }
 }
 
@@ -70,6 +147,11 @@ decl_error! {
         KittiesIdOverflow,
         InvalidKittyId,
         SameGender,
+        NotKittyOwner,
+        KittyNotForSale,
+        PriceTooLow,
+        BuyerIsOwner,
+        DnaCollision,
     }
 }
 
@@ -79,19 +161,44 @@ decl_module! {
 
         fn deposit_event() = default;
 
+        /// `Kitty` grew a `gen` counter and a `parents` field alongside its
+        /// `dna`, changing its SCALE encoding. This is a no-op, not a stub:
+        /// this crate has no `runtime` in this repository and has never been
+        /// built into a chain spec, so there is no live chain with v0-encoded
+        /// `Kitty` values to translate, and the one-time upgrade cost is
+        /// genuinely zero. The day a runtime includes this pallet on a live
+        /// chain, the *next* encoding change must replace this with a real
+        /// `Kitties::translate` migration (and a weight that reflects its
+        /// reads/writes) — this one is exempt only because nothing has ever
+        /// been deployed against the old layout.
+        fn on_runtime_upgrade() -> Weight {
+            0
+        }
+
         #[weight = 1000]
         pub fn create(origin) {
             let sender = ensure_signed(origin)?;
 
+            let dna = Self::random_value(&sender);
+            ensure!(!KittyDnaExists::contains_key(&dna), Error::<T>::DnaCollision);
+
             // get_next_kitty_id mutates state, so we have to make sure
             // there aren't any other possible errors after this
             let current_id = Self::get_next_kitty_id()?;
-            let dna = Self::random_value(&sender);
 
-            let kitty = Kitty(dna);
+            let kitty = KittyOf::<T> {
+                dna,
+                gen: 0,
+                parents: None,
+            };
             Kitties::<T>::insert(&sender, current_id, kitty.clone());
+            Self::insert_owned_kitty(&sender, current_id);
+            KittyDnaExists::insert(dna, true);
+            let total = Self::insert_all_kitty(current_id);
 
-            Self::deposit_event(RawEvent::KittyCreated(sender, current_id, kitty));
+            Self::deposit_event(RawEvent::KittyCreated(sender.clone(), current_id, kitty));
+            Self::deposit_event(RawEvent::TotalKittiesChanged(total));
+            T::MessageSink::send(KittyMessage::Created(sender, current_id, dna));
         }
 
         #[weight = 1000]
@@ -102,10 +209,8 @@ decl_module! {
 
             ensure!(kitty1.gender() != kitty2.gender(), Error::<T>::SameGender);
 
-            let kitty_id = Self::get_next_kitty_id()?;
-
-            let kitty1_dna = kitty1.0;
-            let kitty2_dna = kitty2.0;
+            let kitty1_dna = kitty1.dna;
+            let kitty2_dna = kitty2.dna;
 
             let selector = Self::random_value(&sender);
             let mut new_dna = [0u8; 16];
@@ -115,11 +220,76 @@ decl_module! {
                 new_dna[i] = combine_dna(kitty1_dna[i], kitty2_dna[i], selector[i]);
             }
 
-            let new_kitty = Kitty(new_dna);
+            ensure!(!KittyDnaExists::contains_key(&new_dna), Error::<T>::DnaCollision);
+
+            // get_next_kitty_id mutates state, so we have to make sure
+            // there aren't any other possible errors after this
+            let kitty_id = Self::get_next_kitty_id()?;
+
+            let gen = kitty1.gen.max(kitty2.gen) + 1;
+            let new_kitty = KittyOf::<T> {
+                dna: new_dna,
+                gen,
+                parents: Some((kitty_id_1, kitty_id_2)),
+            };
 
             Kitties::<T>::insert(&sender, kitty_id, &new_kitty);
+            Self::insert_owned_kitty(&sender, kitty_id);
+            KittyDnaExists::insert(new_dna, true);
+            let total = Self::insert_all_kitty(kitty_id);
+
+            Self::deposit_event(RawEvent::KittyBred(sender.clone(), kitty_id, new_kitty, gen));
+            Self::deposit_event(RawEvent::TotalKittiesChanged(total));
+            T::MessageSink::send(KittyMessage::Created(sender, kitty_id, new_dna));
+        }
+
+        #[weight = 1000]
+        pub fn transfer(origin, to: T::AccountId, kitty_id: T::KittyId) {
+            let sender = ensure_signed(origin)?;
 
-            Self::deposit_event(RawEvent::KittyBred(sender, kitty_id, new_kitty));
+            ensure!(Self::kitties(&sender, kitty_id).is_some(), Error::<T>::NotKittyOwner);
+            Self::do_transfer(&sender, &to, kitty_id);
+
+            Self::deposit_event(RawEvent::KittyTransferred(sender.clone(), to.clone(), kitty_id));
+            T::MessageSink::send(KittyMessage::Transferred {
+                from: sender,
+                to,
+                kitty_id,
+            });
+        }
+
+        #[weight = 1000]
+        pub fn set_price(origin, kitty_id: T::KittyId, new_price: Option<BalanceOf<T>>) {
+            let sender = ensure_signed(origin)?;
+
+            ensure!(Self::kitties(&sender, kitty_id).is_some(), Error::<T>::InvalidKittyId);
+
+            match new_price {
+                Some(price) => KittyPrices::<T>::insert(&sender, kitty_id, price),
+                None => KittyPrices::<T>::remove(&sender, kitty_id),
+            }
+        }
+
+        #[weight = 1000]
+        pub fn buy(origin, owner: T::AccountId, kitty_id: T::KittyId, max_price: BalanceOf<T>) {
+            let buyer = ensure_signed(origin)?;
+
+            ensure!(buyer != owner, Error::<T>::BuyerIsOwner);
+            ensure!(Self::kitties(&owner, kitty_id).is_some(), Error::<T>::InvalidKittyId);
+
+            let price = Self::kitty_price(&owner, kitty_id).ok_or(Error::<T>::KittyNotForSale)?;
+            ensure!(price <= max_price, Error::<T>::PriceTooLow);
+
+            T::Currency::transfer(&buyer, &owner, price, ExistenceRequirement::KeepAlive)?;
+
+            Self::do_transfer(&owner, &buyer, kitty_id);
+
+            Self::deposit_event(RawEvent::KittySold(buyer.clone(), owner.clone(), kitty_id, price));
+            T::MessageSink::send(KittyMessage::Transferred {
+                from: owner,
+                to: buyer,
+                kitty_id,
+            });
         }
     }
 }
@@ -129,6 +299,13 @@ pub fn combine_dna(dna1: u8, dna2: u8, selector: u8) -> u8 {
 }
 
 impl<T: Config> Module<T> {
+    /// Resolves a kitty from its global id, regardless of who currently owns
+    /// it. Intended for off-chain clients paginating `AllKittiesArray`.
+    pub fn kitty_by_id(kitty_id: T::KittyId) -> Option<KittyOf<T>> {
+        let owner = KittyOwner::<T>::try_get(kitty_id).ok()?;
+        Self::kitties(owner, kitty_id)
+    }
+
     fn get_next_kitty_id() -> sp_std::result::Result<T::KittyId, DispatchError> {
         NextKittyId::try_mutate(
             |next_id| -> sp_std::result::Result<T::KittyId, DispatchError> {
@@ -141,13 +318,82 @@ impl<T: Config> Module<T> {
         )
     }
 
+    /// Moves a kitty (and its owner index entries) from `from` to `to`.
+    /// Callers are responsible for checking that `from` actually owns the
+    /// kitty before calling this. Any listing `from` had on the kitty is
+    /// cleared, since a price set by a previous owner must never survive a
+    /// change of ownership.
+    fn do_transfer(from: &T::AccountId, to: &T::AccountId, kitty_id: T::KittyId) {
+        let kitty = Self::kitties(from, kitty_id).expect("caller checked ownership; qed");
+
+        Kitties::<T>::remove(from, kitty_id);
+        Self::remove_owned_kitty(from, kitty_id);
+        KittyPrices::<T>::remove(from, kitty_id);
+
+        Kitties::<T>::insert(to, kitty_id, kitty);
+        Self::insert_owned_kitty(to, kitty_id);
+    }
+
     fn random_value(sender: &T::AccountId) -> [u8; 16] {
+        let nonce = Self::get_and_increment_nonce();
         let payload = (
             T::Randomness::random_seed(),
             &sender,
             <frame_system::Module<T>>::extrinsic_index(),
+            nonce,
         );
 
         payload.using_encoded(blake2_128)
     }
+
+    fn get_and_increment_nonce() -> u64 {
+        Nonce::mutate(|nonce| {
+            let current = *nonce;
+            *nonce = nonce.wrapping_add(1);
+            current
+        })
+    }
+
+    /// Appends `kitty_id` to `owner`'s owned kitty list, recording its slot so
+    /// it can later be removed with `remove_owned_kitty`.
+    fn insert_owned_kitty(owner: &T::AccountId, kitty_id: T::KittyId) {
+        let next_index = Self::owned_kitties_count(owner);
+
+        OwnedKittiesIndex::<T>::insert(owner, next_index, kitty_id);
+        KittyIndex::<T>::insert(owner, kitty_id, next_index);
+        OwnedKittiesCount::<T>::insert(owner, next_index + 1);
+        KittyOwner::<T>::insert(kitty_id, owner);
+    }
+
+    /// Appends `kitty_id` to the global kitty registry. Only called on mint;
+    /// there is no burn dispatchable, so the array never needs to shrink.
+    /// Returns the new total number of kitties.
+    fn insert_all_kitty(kitty_id: T::KittyId) -> u64 {
+        let index = Self::all_kitties_count();
+
+        AllKittiesArray::<T>::insert(index, kitty_id);
+        AllKittiesIndex::<T>::insert(kitty_id, index);
+
+        let total = index + 1;
+        AllKittiesCount::put(total);
+        total
+    }
+
+    /// Removes `kitty_id` from `owner`'s owned kitty list using the
+    /// swap-and-pop technique: the last entry is moved into the removed
+    /// kitty's slot so the array stays dense without shifting every element.
+    fn remove_owned_kitty(owner: &T::AccountId, kitty_id: T::KittyId) {
+        let index = Self::kitty_index(owner, kitty_id);
+        let last_index = Self::owned_kitties_count(owner) - 1;
+
+        if index != last_index {
+            let last_kitty_id = Self::owned_kitties_index(owner, last_index);
+            OwnedKittiesIndex::<T>::insert(owner, index, last_kitty_id);
+            KittyIndex::<T>::insert(owner, last_kitty_id, index);
+        }
+
+        OwnedKittiesIndex::<T>::remove(owner, last_index);
+        KittyIndex::<T>::remove(owner, kitty_id);
+        OwnedKittiesCount::<T>::insert(owner, last_index);
+    }
 }